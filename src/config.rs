@@ -1,11 +1,15 @@
 //! # Key-value configuration management
 
-use anyhow::Result;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use num_traits::ToPrimitive;
 use strum::{EnumProperty, IntoEnumIterator};
 use strum_macros::{AsRefStr, Display, EnumIter, EnumProperty, EnumString};
 
 use crate::blob::BlobObject;
 use crate::chat::ChatId;
+use crate::constants;
 use crate::constants::DC_VERSION_STR;
 use crate::context::Context;
 use crate::dc_tools::{dc_get_abs_path, improve_single_line_input};
@@ -36,6 +40,15 @@ pub enum Config {
     SmtpCertificateChecks,
     ServerFlags,
 
+    /// Hostname of the ManageSieve server used to install server-side filters.
+    SieveServer,
+    SievePort,
+
+    /// Whether to install a server-side Sieve filter that moves Chat-Version-tagged
+    /// messages into the DeltaChat folder, reducing the work `MvboxMove` has to do.
+    #[strum(props(default = "0"))]
+    SieveEnabled,
+
     Displayname,
     Selfstatus,
     Selfavatar,
@@ -98,6 +111,19 @@ pub enum Config {
     #[strum(props(default = "0"))]
     DeleteDeviceAfter,
 
+    /// If set to "1", any `local+tag@domain` variant of the configured address (the
+    /// part after `SubaddressingSeparator` is the "tag") is recognized as belonging to
+    /// the account, the same way a catch-all/subaddressing-capable mailbox would
+    /// deliver all of them to `local@domain`. Used to collapse one-address-per-topic
+    /// bot setups into a single self-identity instead of spawning spurious contacts.
+    #[strum(props(default = "0"))]
+    SubaddressingEnabled,
+
+    /// The "detail separator" character that introduces the tag in a subaddress, e.g.
+    /// `+` in `local+tag@domain`. Only takes effect if `SubaddressingEnabled` is set.
+    #[strum(props(default = "+"))]
+    SubaddressingSeparator,
+
     SaveMimeHeaders,
     ConfiguredAddr,
     ConfiguredMailServer,
@@ -112,6 +138,9 @@ pub enum Config {
     ConfiguredSendPort,
     ConfiguredSmtpCertificateChecks,
     ConfiguredServerFlags,
+    ConfiguredSieveServer,
+    ConfiguredSievePort,
+    ConfiguredSieveEnabled,
     ConfiguredSendSecurity,
     ConfiguredE2EEEnabled,
     ConfiguredInboxFolder,
@@ -131,6 +160,12 @@ pub enum Config {
     #[strum(serialize = "sys.config_keys")]
     SysConfigKeys,
 
+    /// A JSON object mapping every config key to its [`ConfigValueKind`], so frontends can
+    /// render an appropriate input (and validate client-side) without hardcoding per-key
+    /// knowledge. See also `sys.config_keys`, which only lists the key names.
+    #[strum(serialize = "sys.config_schema")]
+    SysConfigSchema,
+
     Bot,
 
     /// Whether we send a warning if the password is wrong (set to false when we send a warning
@@ -147,6 +182,17 @@ pub enum Config {
     /// To how many seconds to debounce scan_all_folders. Used mainly in tests, to disable debouncing completely.
     #[strum(props(default = "60"))]
     ScanAllFoldersDebounceSecs,
+
+    /// If set to "1", CONDSTORE/QRESYNC incremental folder sync is never used, even if the
+    /// server supports it, and every scan falls back to the full-folder-listing behavior.
+    /// Used mainly in tests, to force deterministic full scans.
+    #[strum(props(default = "0"))]
+    DisableIncrementalFolderSync,
+
+    /// How many IMAP connections `scan_folders` may open concurrently to fetch
+    /// non-watched folders in parallel.
+    #[strum(props(default = "3"))]
+    MaxConcurrentImapConnections,
 }
 
 impl Context {
@@ -164,6 +210,7 @@ impl Context {
             Config::SysVersion => Some((&*DC_VERSION_STR).clone()),
             Config::SysMsgsizeMaxRecommended => Some(format!("{}", RECOMMENDED_FILE_SIZE)),
             Config::SysConfigKeys => Some(get_config_keys_string()),
+            Config::SysConfigSchema => Some(get_config_schema_string()),
             _ => self.sql.get_raw_config(key).await?,
         };
 
@@ -235,9 +282,39 @@ impl Context {
         }
     }
 
+    /// Checks whether `addr` is the configured self-address, possibly after stripping a
+    /// subaddressing tag (e.g. `me+news@example.com` is "self" if `me@example.com` is the
+    /// configured address and [`Config::SubaddressingEnabled`] is set).
+    ///
+    /// Used while importing fetched messages to decide whether a recipient is "self"
+    /// instead of a spurious per-tag contact.
+    pub async fn is_self_addr_with_subaddressing(&self, addr: &str) -> Result<bool> {
+        let self_addr = match self.get_config(Config::ConfiguredAddr).await? {
+            Some(self_addr) => self_addr,
+            None => return Ok(false),
+        };
+        if addr.eq_ignore_ascii_case(&self_addr) {
+            return Ok(true);
+        }
+        if !self.get_config_bool(Config::SubaddressingEnabled).await? {
+            return Ok(false);
+        }
+        let separator = self
+            .get_config(Config::SubaddressingSeparator)
+            .await?
+            .unwrap_or_else(|| "+".to_string());
+        match strip_subaddress_tag(addr, &separator) {
+            Some(stripped) => Ok(stripped.eq_ignore_ascii_case(&self_addr)),
+            None => Ok(false),
+        }
+    }
+
     /// Set the given config key.
     /// If `None` is passed as a value the value is cleared and set to the default if there is one.
     pub async fn set_config(&self, key: Config, value: Option<&str>) -> Result<()> {
+        if let Some(value) = value {
+            validate_config_value(key, value)?;
+        }
         match key {
             Config::Selfavatar => {
                 self.sql
@@ -288,6 +365,13 @@ impl Context {
                 self.sql.set_raw_config(key, value.as_deref()).await?;
                 Ok(())
             }
+            Config::SieveEnabled => {
+                self.sql.set_raw_config(key, value).await?;
+                if value == Some("1") {
+                    self.configure_sieve().await?;
+                }
+                Ok(())
+            }
             _ => {
                 self.sql.set_raw_config(key, value).await?;
                 Ok(())
@@ -300,6 +384,295 @@ impl Context {
             .await?;
         Ok(())
     }
+
+    /// Serializes every `Config` key that has a value (set, or a default from `get_config`)
+    /// into a single TOML document, keyed by the same
+    /// snake_case names `get_config`/`set_config` use.
+    ///
+    /// Secret-bearing keys (`mail_pw`, `send_pw` and their `configured_*` counterparts) are
+    /// left out unless `include_secrets` is set. Computed `sys.*` virtual keys are never
+    /// included, since they can't be meaningfully re-imported.
+    pub async fn export_config_toml(&self, include_secrets: bool) -> Result<String> {
+        let mut table = toml::value::Table::new();
+        for key in Config::iter() {
+            if is_sys_key(key) || (is_secret_key(key) && !include_secrets) {
+                continue;
+            }
+            if let Some(value) = self.get_config(key).await? {
+                table.insert(key.to_string(), config_value_to_toml(key, &value)?);
+            }
+        }
+        Ok(toml::to_string_pretty(&toml::Value::Table(table))?)
+    }
+
+    /// Parses a TOML document produced by [`Context::export_config_toml`] (or handwritten
+    /// in the same shape) and applies it key by key, validating every value against the
+    /// type/range implied by its `Config` variant before writing anything. Unknown keys are
+    /// rejected rather than silently ignored.
+    pub async fn import_config_toml(&self, toml_str: &str) -> Result<()> {
+        let doc: toml::Value = toml::from_str(toml_str)?;
+        let table = doc
+            .as_table()
+            .ok_or_else(|| anyhow::anyhow!("config TOML must be a table of key = value pairs"))?;
+
+        // Validate eagerly so a typo deep in the document can't leave the config half-applied.
+        let mut parsed = Vec::with_capacity(table.len());
+        for (name, value) in table {
+            let key = Config::from_str(name)
+                .map_err(|_| anyhow::anyhow!("unknown config key in TOML import: {}", name))?;
+            if is_sys_key(key) {
+                bail!("{} is a computed key and cannot be imported", name);
+            }
+            let value = validate_toml_config_value(key, value)?;
+            parsed.push((key, value));
+        }
+
+        for (key, value) in parsed {
+            self.set_config(key, Some(&value)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Keys whose value should never be exported unless the caller explicitly asks for secrets.
+fn is_secret_key(key: Config) -> bool {
+    matches!(
+        key,
+        Config::MailPw | Config::SendPw | Config::ConfiguredMailPw | Config::ConfiguredSendPw
+    )
+}
+
+/// Computed `sys.*` keys: read-only, derived at lookup time, never stored.
+fn is_sys_key(key: Config) -> bool {
+    matches!(
+        key,
+        Config::SysVersion
+            | Config::SysMsgsizeMaxRecommended
+            | Config::SysConfigKeys
+            | Config::SysConfigSchema
+    )
+}
+
+/// Keys backed by a "0"/"1" string, as used by `get_config_bool`/`set_config_bool`.
+fn is_bool_key(key: Config) -> bool {
+    matches!(
+        key,
+        Config::BccSelf
+            | Config::E2eeEnabled
+            | Config::MdnsEnabled
+            | Config::InboxWatch
+            | Config::SentboxWatch
+            | Config::MvboxWatch
+            | Config::MvboxMove
+            | Config::SentboxMove
+            | Config::FetchExistingMsgs
+            | Config::NotifyAboutWrongPw
+            | Config::SieveEnabled
+            | Config::ConfiguredSieveEnabled
+            | Config::ConfiguredE2EEEnabled
+            | Config::SubaddressingEnabled
+            | Config::DisableIncrementalFolderSync
+    )
+}
+
+/// Keys whose value is an integer (port numbers, timers, enum ordinals, ...).
+fn is_int_key(key: Config) -> bool {
+    matches!(
+        key,
+        Config::MailPort
+            | Config::SendPort
+            | Config::ServerFlags
+            | Config::ImapCertificateChecks
+            | Config::SmtpCertificateChecks
+            | Config::ShowEmails
+            | Config::MediaQuality
+            | Config::KeyGenType
+            | Config::DeleteServerAfter
+            | Config::DeleteDeviceAfter
+            | Config::LastHousekeeping
+            | Config::ScanAllFoldersDebounceSecs
+            | Config::MaxConcurrentImapConnections
+            | Config::SievePort
+            | Config::ConfiguredMailPort
+            | Config::ConfiguredSendPort
+            | Config::ConfiguredServerFlags
+            | Config::ConfiguredImapCertificateChecks
+            | Config::ConfiguredSmtpCertificateChecks
+            | Config::ConfiguredSievePort
+            | Config::ConfiguredTimestamp
+    )
+}
+
+/// Converts a raw, already-validated config string into the TOML value shape it should
+/// render as (bools/ints as native TOML types, everything else as a string).
+fn config_value_to_toml(key: Config, value: &str) -> Result<toml::Value> {
+    if is_bool_key(key) {
+        return Ok(toml::Value::Boolean(value != "0"));
+    }
+    if is_int_key(key) {
+        if let Ok(n) = value.parse::<i64>() {
+            return Ok(toml::Value::Integer(n));
+        }
+    }
+    Ok(toml::Value::String(value.to_string()))
+}
+
+/// Converts a TOML value coming from [`Context::import_config_toml`] into the string form
+/// `set_config` stores (bool/int vs. string), then checks the result with
+/// [`validate_config_value`] so an invalid value is rejected before anything is written.
+fn validate_toml_config_value(key: Config, value: &toml::Value) -> Result<String> {
+    let name = key.to_string();
+    let as_string = if is_bool_key(key) {
+        let b = match value {
+            toml::Value::Boolean(b) => *b,
+            toml::Value::Integer(0) => false,
+            toml::Value::Integer(1) => true,
+            _ => bail!("{} is a bool key and only accepts true/false (or 0/1)", name),
+        };
+        if b { "1" } else { "0" }.to_string()
+    } else if is_int_key(key) {
+        let n = value
+            .as_integer()
+            .ok_or_else(|| anyhow::anyhow!("{} must be an integer", name))?;
+        n.to_string()
+    } else {
+        match value {
+            toml::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }
+    };
+    validate_config_value(key, &as_string)?;
+    Ok(as_string)
+}
+
+/// Machine-readable description of the value a `Config` key accepts, so frontends can
+/// render an appropriate input and validate client-side instead of discovering a bad value
+/// only once `set_config` rejects it (or, historically, silently coerced it to `0`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValueKind {
+    /// A "0"/"1" string, as used by `get_config_bool`/`set_config_bool`.
+    Bool,
+    /// An integer, optionally bounded.
+    Int { min: Option<i64>, max: Option<i64> },
+    /// One of a small, fixed set of integer variants (e.g. `MediaQuality`), derived from the
+    /// corresponding Rust enum so a new variant is picked up automatically instead of needing
+    /// a second, easy-to-forget edit here.
+    Enum(Vec<i64>),
+    /// An arbitrary string.
+    Str,
+    /// A filesystem path (e.g. `Selfavatar`).
+    Path,
+    /// A secret value (e.g. `MailPw`), left out of `export_config_toml` unless secrets are
+    /// explicitly included.
+    Secret,
+}
+
+impl Config {
+    /// Returns this key's [`ConfigValueKind`], exposed to frontends via `sys.config_schema`
+    /// and used internally by `set_config` to validate values before writing them.
+    pub fn meta(self) -> ConfigValueKind {
+        if is_secret_key(self) {
+            return ConfigValueKind::Secret;
+        }
+        if is_bool_key(self) {
+            return ConfigValueKind::Bool;
+        }
+        match self {
+            Config::Selfavatar => ConfigValueKind::Path,
+            Config::MediaQuality => {
+                ConfigValueKind::Enum(enum_variant_values::<constants::MediaQuality>())
+            }
+            Config::ShowEmails => {
+                ConfigValueKind::Enum(enum_variant_values::<constants::ShowEmails>())
+            }
+            Config::DeleteServerAfter | Config::DeleteDeviceAfter => ConfigValueKind::Int {
+                min: Some(0),
+                max: None,
+            },
+            Config::MailPort
+            | Config::SendPort
+            | Config::SievePort
+            | Config::ConfiguredMailPort
+            | Config::ConfiguredSendPort
+            | Config::ConfiguredSievePort => ConfigValueKind::Int {
+                min: Some(0),
+                max: Some(65535),
+            },
+            _ if is_int_key(self) => ConfigValueKind::Int {
+                min: None,
+                max: None,
+            },
+            _ => ConfigValueKind::Str,
+        }
+    }
+}
+
+/// Collects every value `T` (one of our small C-like enums, e.g. `constants::MediaQuality`)
+/// can take, by iterating its variants rather than hand-listing them, so [`Config::meta`]
+/// can't drift out of sync with the enum it describes.
+fn enum_variant_values<T: IntoEnumIterator + ToPrimitive>() -> Vec<i64> {
+    T::iter().filter_map(|v| v.to_i64()).collect()
+}
+
+/// Validates `value` against the type/range implied by `key`'s [`Config::meta`].
+fn validate_config_value(key: Config, value: &str) -> Result<()> {
+    let name = key.to_string();
+    match key.meta() {
+        ConfigValueKind::Bool => {
+            if value != "0" && value != "1" {
+                bail!("{} is a bool key and only accepts 0 or 1", name);
+            }
+        }
+        ConfigValueKind::Int { min, max } => {
+            let n: i64 = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{} must be an integer", name))?;
+            if min.map_or(false, |min| n < min) || max.map_or(false, |max| n > max) {
+                bail!("{} value {} is out of range", name, n);
+            }
+        }
+        ConfigValueKind::Enum(variants) => {
+            let n: i64 = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{} must be an integer", name))?;
+            if !variants.contains(&n) {
+                bail!("{} is not a valid value for {}", n, name);
+            }
+        }
+        ConfigValueKind::Str | ConfigValueKind::Path | ConfigValueKind::Secret => {}
+    }
+    Ok(())
+}
+
+/// Returns a JSON object mapping every config key to its [`ConfigValueKind`].
+fn get_config_schema_string() -> String {
+    let schema: serde_json::Map<String, serde_json::Value> = Config::iter()
+        .map(|key| (key.to_string(), config_value_kind_to_json(key.meta())))
+        .collect();
+    serde_json::Value::Object(schema).to_string()
+}
+
+fn config_value_kind_to_json(kind: ConfigValueKind) -> serde_json::Value {
+    match kind {
+        ConfigValueKind::Bool => serde_json::json!({"kind": "bool"}),
+        ConfigValueKind::Int { min, max } => serde_json::json!({"kind": "int", "min": min, "max": max}),
+        ConfigValueKind::Enum(variants) => serde_json::json!({"kind": "enum", "variants": variants}),
+        ConfigValueKind::Str => serde_json::json!({"kind": "str"}),
+        ConfigValueKind::Path => serde_json::json!({"kind": "path"}),
+        ConfigValueKind::Secret => serde_json::json!({"kind": "secret"}),
+    }
+}
+
+/// Strips the `<separator><tag>` portion of a `local<separator><tag>@domain` address,
+/// returning `local@domain`. Returns `None` if `addr` has no local part, domain part, or
+/// `separator` (in which case it isn't a subaddress at all).
+fn strip_subaddress_tag(addr: &str, separator: &str) -> Option<String> {
+    let (local, domain) = addr.split_once('@')?;
+    let (base, _tag) = local.split_once(separator)?;
+    if base.is_empty() {
+        return None;
+    }
+    Some(format!("{}@{}", base, domain))
 }
 
 /// Returns all available configuration keys concated together.
@@ -338,6 +711,46 @@ mod tests {
             Config::from_str("sys.config_keys"),
             Ok(Config::SysConfigKeys)
         );
+
+        assert_eq!(Config::SysConfigSchema.to_string(), "sys.config_schema");
+        assert_eq!(
+            Config::from_str("sys.config_schema"),
+            Ok(Config::SysConfigSchema)
+        );
+    }
+
+    #[test]
+    fn test_config_meta() {
+        assert_eq!(Config::BccSelf.meta(), ConfigValueKind::Bool);
+        assert_eq!(Config::MailPw.meta(), ConfigValueKind::Secret);
+        assert_eq!(Config::MediaQuality.meta(), ConfigValueKind::Enum(vec![0, 1]));
+        assert_eq!(
+            Config::MailPort.meta(),
+            ConfigValueKind::Int {
+                min: Some(0),
+                max: Some(65535)
+            }
+        );
+    }
+
+    #[async_std::test]
+    async fn test_sys_config_schema() {
+        let t = TestContext::new().await;
+        let schema = t.get_config(Config::SysConfigSchema).await.unwrap().unwrap();
+        let schema: serde_json::Value = serde_json::from_str(&schema).unwrap();
+        assert_eq!(schema["bcc_self"]["kind"], "bool");
+        assert_eq!(schema["media_quality"]["kind"], "enum");
+        assert_eq!(schema["mail_pw"]["kind"], "secret");
+    }
+
+    #[async_std::test]
+    async fn test_set_config_validates_media_quality() {
+        let t = TestContext::new().await;
+        assert!(t
+            .set_config(Config::MediaQuality, Some("99"))
+            .await
+            .is_err());
+        assert!(t.set_config(Config::MediaQuality, Some("1")).await.is_ok());
     }
 
     #[async_std::test]
@@ -432,4 +845,84 @@ mod tests {
         let media_quality = constants::MediaQuality::from_i32(media_quality).unwrap_or_default();
         assert_eq!(media_quality, constants::MediaQuality::Worse);
     }
+
+    #[async_std::test]
+    async fn test_is_self_addr_with_subaddressing() {
+        let t = TestContext::new().await;
+        t.set_config(Config::ConfiguredAddr, Some("me@example.com"))
+            .await
+            .unwrap();
+
+        assert!(t.is_self_addr_with_subaddressing("me@example.com").await.unwrap());
+        assert!(!t
+            .is_self_addr_with_subaddressing("me+news@example.com")
+            .await
+            .unwrap());
+
+        t.set_config(Config::SubaddressingEnabled, Some("1"))
+            .await
+            .unwrap();
+        assert!(t
+            .is_self_addr_with_subaddressing("me+news@example.com")
+            .await
+            .unwrap());
+        assert!(!t
+            .is_self_addr_with_subaddressing("other@example.com")
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn test_strip_subaddress_tag() {
+        assert_eq!(
+            strip_subaddress_tag("me+news@example.com", "+"),
+            Some("me@example.com".to_string())
+        );
+        assert_eq!(strip_subaddress_tag("me@example.com", "+"), None);
+    }
+
+    #[async_std::test]
+    async fn test_config_toml_roundtrip() {
+        let t = TestContext::new().await;
+        t.set_config(Config::Addr, Some("me@example.com"))
+            .await
+            .unwrap();
+        t.set_config(Config::BccSelf, Some("1")).await.unwrap();
+        t.set_config(Config::MailPw, Some("hunter2")).await.unwrap();
+
+        let without_secrets = t.export_config_toml(false).await.unwrap();
+        assert!(!without_secrets.contains("hunter2"));
+        assert!(without_secrets.contains("me@example.com"));
+
+        let with_secrets = t.export_config_toml(true).await.unwrap();
+        assert!(with_secrets.contains("hunter2"));
+
+        let t2 = TestContext::new().await;
+        t2.import_config_toml(&with_secrets).await.unwrap();
+        assert_eq!(
+            t2.get_config(Config::Addr).await.unwrap(),
+            Some("me@example.com".to_string())
+        );
+        assert!(t2.get_config_bool(Config::BccSelf).await.unwrap());
+        assert_eq!(
+            t2.get_config(Config::MailPw).await.unwrap(),
+            Some("hunter2".to_string())
+        );
+    }
+
+    #[async_std::test]
+    async fn test_config_toml_import_rejects_unknown_key() {
+        let t = TestContext::new().await;
+        assert!(t
+            .import_config_toml("not_a_real_key = \"x\"")
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_config_toml_import_validates_bool() {
+        let t = TestContext::new().await;
+        assert!(t.import_config_toml("bcc_self = \"yes\"").await.is_err());
+        assert!(t.import_config_toml("bcc_self = 1").await.is_ok());
+    }
 }
@@ -0,0 +1,422 @@
+//! CONDSTORE/QRESYNC support for incremental folder sync.
+//!
+//! When the server advertises the `CONDSTORE` or `QRESYNC` capability, every
+//! folder has a monotonically increasing 64-bit `HIGHESTMODSEQ` that is
+//! bumped whenever a message's flags change or a new message arrives.
+//! Remembering the last value we have seen lets us ask the server only for
+//! what changed (`FETCH ... (CHANGEDSINCE <modseq>)`) instead of
+//! re-examining the whole folder, and `VANISHED (EARLIER)` tells us which
+//! UIDs were expunged without a full UID scan.
+//!
+//! The persisted state is keyed by folder name and is only valid for as
+//! long as the folder's `UIDVALIDITY` does not change; if it does, the
+//! folder was recreated and the stored MODSEQ is meaningless, so we discard
+//! it and fall back to a full resync.
+
+use anyhow::{Context as _, Result};
+
+use crate::config::Config;
+use crate::context::Context;
+use crate::imap::Imap;
+
+/// Persisted CONDSTORE/QRESYNC state for a single folder: the `HIGHESTMODSEQ` we last saw,
+/// together with the `UIDVALIDITY` it was observed under (a stored `HIGHESTMODSEQ` is only
+/// meaningful alongside the `UIDVALIDITY` it was paired with -- the folder may have been
+/// recreated since).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FolderModseq {
+    pub uidvalidity: u32,
+    pub highestmodseq: u64,
+}
+
+impl FolderModseq {
+    fn to_config_value(self) -> String {
+        format!("{}:{}", self.uidvalidity, self.highestmodseq)
+    }
+
+    fn from_config_value(value: &str) -> Option<Self> {
+        let (uidvalidity, highestmodseq) = value.split_once(':')?;
+        Some(Self {
+            uidvalidity: uidvalidity.parse().ok()?,
+            highestmodseq: highestmodseq.parse().ok()?,
+        })
+    }
+}
+
+/// Which CONDSTORE-family extension (if any) `Imap::enable_condstore` turned on. `VANISHED` in
+/// a `UID FETCH ... CHANGEDSINCE` is only legal once `QRESYNC` -- not plain `CONDSTORE` -- is
+/// enabled (RFC 7162 §3.6); callers must check this before asking for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CondstoreExtension {
+    /// Neither extension is available (or incremental sync is disabled): fall back to a full
+    /// scan of every folder.
+    None,
+    Condstore,
+    Qresync,
+}
+
+/// The result of comparing a folder's current MODSEQ/UID state against what we last observed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FolderChange {
+    /// `HIGHESTMODSEQ` is unchanged since the last scan: nothing to do at all.
+    Unchanged,
+    /// `HIGHESTMODSEQ` moved, but `FETCH ... CHANGEDSINCE` shows only flags changed (or
+    /// messages were expunged) on UIDs we already know about -- no UID at or beyond the
+    /// folder's previous `UIDNEXT`, so there is no new mail to import.
+    FlagsOrExpungeOnly,
+    /// At least one changed UID is at or beyond the folder's previous `UIDNEXT`: there is new
+    /// mail, so the caller should fall back to a full `fetch_new_messages`.
+    NewMail,
+}
+
+fn modseq_config_key(folder: &str) -> String {
+    format!("folder.{}.highestmodseq", folder)
+}
+
+fn uidnext_config_key(folder: &str) -> String {
+    format!("folder.{}.uidnext", folder)
+}
+
+/// Loads the persisted [`FolderModseq`] for `folder`, if any.
+async fn load_modseq(context: &Context, folder: &str) -> Result<Option<FolderModseq>> {
+    let key = modseq_config_key(folder);
+    let stored = context.sql.get_raw_config(&key).await?;
+    Ok(stored.as_deref().and_then(FolderModseq::from_config_value))
+}
+
+/// Persists `modseq` as the last-seen CONDSTORE/QRESYNC state for `folder`.
+async fn store_modseq(context: &Context, folder: &str, modseq: FolderModseq) -> Result<()> {
+    let key = modseq_config_key(folder);
+    context
+        .sql
+        .set_raw_config(&key, Some(&modseq.to_config_value()))
+        .await?;
+    Ok(())
+}
+
+/// Loads the `UIDNEXT` we observed the last time we scanned `folder`, if any.
+async fn load_uidnext(context: &Context, folder: &str) -> Result<Option<u32>> {
+    let key = uidnext_config_key(folder);
+    Ok(context
+        .sql
+        .get_raw_config(&key)
+        .await?
+        .and_then(|v| v.parse().ok()))
+}
+
+/// Persists the `UIDNEXT` observed for `folder`.
+async fn store_uidnext(context: &Context, folder: &str, uidnext: u32) -> Result<()> {
+    let key = uidnext_config_key(folder);
+    context
+        .sql
+        .set_raw_config(&key, Some(&uidnext.to_string()))
+        .await?;
+    Ok(())
+}
+
+/// Parses an IMAP UID/sequence set such as `1,3,5:7,42` into the individual UIDs it denotes.
+/// Used to decode the UID list of a `* VANISHED (EARLIER) <uid-set>` response line.
+fn parse_uid_set(s: &str) -> Vec<u32> {
+    let mut uids = Vec::new();
+    for part in s.split(',') {
+        match part.split_once(':') {
+            Some((start, end)) => {
+                if let (Ok(start), Ok(end)) = (start.parse::<u32>(), end.parse::<u32>()) {
+                    uids.extend(start.min(end)..=start.max(end));
+                }
+            }
+            None => {
+                if let Ok(uid) = part.trim().parse::<u32>() {
+                    uids.push(uid);
+                }
+            }
+        }
+    }
+    uids
+}
+
+impl Imap {
+    /// Detects `CONDSTORE`/`QRESYNC` support and `ENABLE`s the better of the two.
+    ///
+    /// Returns [`CondstoreExtension::None`] if incremental sync was disabled via
+    /// [`Config::DisableIncrementalFolderSync`] (without touching the connection) or if the
+    /// server lacks both capabilities (without sending `ENABLE`), in which case callers must
+    /// fall back to the full-scan behaviour. Callers that want to use `VANISHED` must check the returned
+    /// extension is [`CondstoreExtension::Qresync`] -- it is not legal under plain `CONDSTORE`.
+    pub(crate) async fn enable_condstore(
+        &mut self,
+        context: &Context,
+    ) -> Result<CondstoreExtension> {
+        if context
+            .get_config_bool(Config::DisableIncrementalFolderSync)
+            .await?
+        {
+            return Ok(CondstoreExtension::None);
+        }
+
+        let session = self
+            .session
+            .as_mut()
+            .context("enable_condstore(): IMAP No Connection established")?;
+        let caps = session.capabilities().await?;
+        let has_qresync = caps.has_str("QRESYNC");
+        let has_condstore = has_qresync || caps.has_str("CONDSTORE");
+        if !has_condstore {
+            return Ok(CondstoreExtension::None);
+        }
+
+        let extension = if has_qresync {
+            CondstoreExtension::Qresync
+        } else {
+            CondstoreExtension::Condstore
+        };
+        let extension_name = if has_qresync { "QRESYNC" } else { "CONDSTORE" };
+        session
+            .run_command_and_check_ok(&format!("ENABLE {}", extension_name))
+            .await
+            .context("enable_condstore(): ENABLE failed")?;
+        Ok(extension)
+    }
+
+    /// Classifies what changed in `folder` since the last scan using `STATUS`,
+    /// `UID FETCH ... CHANGEDSINCE` and `VANISHED (EARLIER)` (RFC 7162), so a caller can skip
+    /// the expensive full rescan not only when nothing at all changed, but also when the only
+    /// changes are flag updates or expunges of mail we've already imported.
+    ///
+    /// Returns [`FolderChange::NewMail`] (never skip) if `STATUS` lacks a needed value or the
+    /// `CHANGEDSINCE` fetch fails, since a false "nothing new" would drop mail, while a false
+    /// "new mail" only costs an unnecessary scan. A failed `STATUS` or database access is
+    /// returned as an error.
+    pub(crate) async fn classify_folder_change(
+        &mut self,
+        context: &Context,
+        folder: &str,
+        extension: CondstoreExtension,
+    ) -> Result<FolderChange> {
+        let session = self
+            .session
+            .as_mut()
+            .context("classify_folder_change(): IMAP No Connection established")?;
+        let status = session
+            .status(folder, "(UIDVALIDITY UIDNEXT HIGHESTMODSEQ)")
+            .await
+            .with_context(|| format!("STATUS failed for folder {}", folder))?;
+        let (uidvalidity, uidnext, highestmodseq) =
+            match (status.uid_validity, status.uid_next, status.highest_mod_seq) {
+                (Some(uidvalidity), Some(uidnext), Some(highestmodseq)) => {
+                    (uidvalidity, uidnext, highestmodseq)
+                }
+                _ => return Ok(FolderChange::NewMail),
+            };
+
+        let stored_modseq = load_modseq(context, folder).await?;
+        let stored_uidnext = load_uidnext(context, folder).await?;
+
+        let result = match stored_modseq {
+            Some(stored)
+                if stored.uidvalidity == uidvalidity && stored.highestmodseq == highestmodseq =>
+            {
+                FolderChange::Unchanged
+            }
+            Some(stored) if stored.uidvalidity == uidvalidity => {
+                let use_vanished = extension == CondstoreExtension::Qresync;
+                match self
+                    .fetch_changed_uids(folder, stored.highestmodseq, use_vanished)
+                    .await
+                {
+                    Ok(changed_uids) => {
+                        let previous_uidnext = stored_uidnext.unwrap_or(0);
+                        if changed_uids.iter().any(|uid| *uid >= previous_uidnext) {
+                            FolderChange::NewMail
+                        } else {
+                            FolderChange::FlagsOrExpungeOnly
+                        }
+                    }
+                    Err(e) => {
+                        warn!(
+                            context,
+                            "CHANGEDSINCE FETCH failed for folder {}: {:#}", folder, e
+                        );
+                        // Don't advance the stored watermark past a window we couldn't
+                        // actually inspect, or the missed mail would be permanently skipped
+                        // instead of merely retried on the next scan.
+                        return Ok(FolderChange::NewMail);
+                    }
+                }
+            }
+            // No (usable) stored state: either we've never scanned this folder, or its
+            // UIDVALIDITY changed (folder recreated) and the old MODSEQ is meaningless.
+            _ => FolderChange::NewMail,
+        };
+
+        store_modseq(
+            context,
+            folder,
+            FolderModseq {
+                uidvalidity,
+                highestmodseq,
+            },
+        )
+        .await?;
+        store_uidnext(context, folder, uidnext).await?;
+        Ok(result)
+    }
+
+    /// Issues `UID FETCH 1:* (UID FLAGS) (CHANGEDSINCE <modseq>[ VANISHED])` and returns the
+    /// UIDs the server reports as changed or vanished since `modseq`.
+    ///
+    /// `use_vanished` must only be set once `QRESYNC` (not plain `CONDSTORE`) is enabled --
+    /// the `VANISHED` FETCH modifier is illegal otherwise (RFC 7162 §3.6) and a CONDSTORE-only
+    /// server would reject the whole command with `BAD`.
+    async fn fetch_changed_uids(
+        &mut self,
+        folder: &str,
+        modseq: u64,
+        use_vanished: bool,
+    ) -> Result<Vec<u32>> {
+        let session = self
+            .session
+            .as_mut()
+            .context("fetch_changed_uids(): IMAP No Connection established")?;
+        session
+            .select(folder)
+            .await
+            .with_context(|| format!("SELECT failed for folder {}", folder))?;
+        let modifier = changedsince_modifier(modseq, use_vanished);
+        let lines = session
+            .run_command_and_collect_lines(&format!("UID FETCH 1:* (UID FLAGS) ({})", modifier))
+            .await
+            .context("UID FETCH CHANGEDSINCE failed")?;
+
+        let mut uids = Vec::new();
+        for line in lines {
+            if let Some(rest) = line
+                .trim_start_matches('*')
+                .trim_start()
+                .strip_prefix("VANISHED (EARLIER) ")
+            {
+                uids.extend(parse_uid_set(rest.trim()));
+            } else if let Some(uid) = extract_fetch_uid(&line) {
+                uids.push(uid);
+            }
+        }
+        Ok(uids)
+    }
+}
+
+/// Builds the `CHANGEDSINCE`/`VANISHED` FETCH modifier text. `VANISHED` is only appended when
+/// `use_vanished` is set, since it is only legal once `QRESYNC` (not plain `CONDSTORE`) is
+/// enabled -- sending it otherwise gets the whole `UID FETCH` rejected with `BAD`.
+fn changedsince_modifier(modseq: u64, use_vanished: bool) -> String {
+    if use_vanished {
+        format!("CHANGEDSINCE {} VANISHED", modseq)
+    } else {
+        format!("CHANGEDSINCE {}", modseq)
+    }
+}
+
+/// Extracts the `UID <n>` token from a single `* <seq> FETCH (...)` response line.
+fn extract_fetch_uid(line: &str) -> Option<u32> {
+    let idx = line.find("UID")?;
+    line[idx + 3..]
+        .trim_start()
+        .split(|c: char| !c.is_ascii_digit())
+        .find(|s| !s.is_empty())?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::TestContext;
+
+    #[test]
+    fn test_folder_modseq_config_value_roundtrip() {
+        let modseq = FolderModseq {
+            uidvalidity: 42,
+            highestmodseq: 123456789,
+        };
+        let encoded = modseq.to_config_value();
+        assert_eq!(FolderModseq::from_config_value(&encoded), Some(modseq));
+    }
+
+    #[test]
+    fn test_folder_modseq_from_config_value_rejects_garbage() {
+        assert_eq!(FolderModseq::from_config_value(""), None);
+        assert_eq!(FolderModseq::from_config_value("not-a-number"), None);
+        assert_eq!(FolderModseq::from_config_value("42"), None);
+    }
+
+    #[test]
+    fn test_parse_uid_set() {
+        assert_eq!(parse_uid_set("1,3,5:7,42"), vec![1, 3, 5, 6, 7, 42]);
+        assert_eq!(parse_uid_set(""), Vec::<u32>::new());
+        assert_eq!(parse_uid_set("7:5"), vec![5, 6, 7]);
+    }
+
+    #[test]
+    fn test_extract_fetch_uid() {
+        assert_eq!(
+            extract_fetch_uid("* 12 FETCH (UID 9001 FLAGS (\\Seen))"),
+            Some(9001)
+        );
+        assert_eq!(extract_fetch_uid("* 12 FETCH (FLAGS (\\Seen))"), None);
+    }
+
+    #[async_std::test]
+    async fn test_modseq_roundtrip() {
+        let t = TestContext::new().await;
+        assert_eq!(load_modseq(&t, "INBOX").await.unwrap(), None);
+
+        let modseq = FolderModseq {
+            uidvalidity: 1,
+            highestmodseq: 100,
+        };
+        store_modseq(&t, "INBOX", modseq).await.unwrap();
+        assert_eq!(load_modseq(&t, "INBOX").await.unwrap(), Some(modseq));
+
+        let bumped = FolderModseq {
+            uidvalidity: 1,
+            highestmodseq: 200,
+        };
+        store_modseq(&t, "INBOX", bumped).await.unwrap();
+        assert_eq!(load_modseq(&t, "INBOX").await.unwrap(), Some(bumped));
+    }
+
+    #[test]
+    fn test_changedsince_modifier_omits_vanished_without_qresync() {
+        // Regression test: VANISHED must never be sent unless QRESYNC (not plain CONDSTORE)
+        // was actually enabled, or a CONDSTORE-only server rejects the whole UID FETCH.
+        assert_eq!(changedsince_modifier(42, false), "CHANGEDSINCE 42");
+        assert_eq!(changedsince_modifier(42, true), "CHANGEDSINCE 42 VANISHED");
+    }
+
+    #[async_std::test]
+    async fn test_uidnext_roundtrip() {
+        let t = TestContext::new().await;
+        assert_eq!(load_uidnext(&t, "INBOX").await.unwrap(), None);
+        store_uidnext(&t, "INBOX", 17).await.unwrap();
+        assert_eq!(load_uidnext(&t, "INBOX").await.unwrap(), Some(17));
+    }
+
+    #[async_std::test]
+    async fn test_disable_incremental_folder_sync_config_roundtrip() {
+        // `Imap::enable_condstore` reads this config before touching the connection at all,
+        // so disabling it short-circuits regardless of server capabilities. This checks
+        // that the setting defaults to off and reads back as on once set.
+        let t = TestContext::new().await;
+        assert!(!t
+            .get_config_bool(Config::DisableIncrementalFolderSync)
+            .await
+            .unwrap());
+        t.set_config(Config::DisableIncrementalFolderSync, Some("1"))
+            .await
+            .unwrap();
+        assert!(t
+            .get_config_bool(Config::DisableIncrementalFolderSync)
+            .await
+            .unwrap());
+    }
+}
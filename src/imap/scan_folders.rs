@@ -4,10 +4,13 @@ use anyhow::{Context as _, Result};
 
 use crate::config::Config;
 use crate::context::Context;
+use crate::imap::condstore::{CondstoreExtension, FolderChange};
 use crate::imap::Imap;
 use crate::log::LogExt;
+use crate::sql::paramsv;
 
 use async_std::stream::StreamExt;
+use futures::stream::{FuturesUnordered, StreamExt as _};
 
 use super::{get_folder_meaning, get_folder_meaning_by_name, FolderMeaning};
 
@@ -28,6 +31,10 @@ impl Imap {
         info!(context, "Starting full folder scan");
 
         self.setup_handle(context).await?;
+        let condstore_extension = self.enable_condstore(context).await.unwrap_or_else(|e| {
+            warn!(context, "Can't enable CONDSTORE/QRESYNC: {:#}", e);
+            CondstoreExtension::None
+        });
         let session = self.session.as_mut();
         let session = session.context("scan_folders(): IMAP No Connection established")?;
         let folders: Vec<_> = session.list(Some(""), Some("*")).await?.collect().await;
@@ -35,6 +42,7 @@ impl Imap {
 
         let mut sentbox_folder = None;
         let mut spam_folder = None;
+        let mut to_fetch = Vec::new();
 
         for folder in folders {
             let folder = match folder {
@@ -68,12 +76,32 @@ impl Imap {
                     context,
                     "Not scanning folder {} as it is watched anyway", foldername
                 );
-            } else {
-                info!(context, "Scanning folder: {}", foldername);
-
-                if let Err(e) = self.fetch_new_messages(context, foldername, false).await {
-                    warn!(context, "Can't fetch new msgs in scanned folder: {:#}", e);
+            } else if condstore_extension != CondstoreExtension::None {
+                match self
+                    .classify_folder_change(context, foldername, condstore_extension)
+                    .await
+                    .unwrap_or(FolderChange::NewMail)
+                {
+                    FolderChange::Unchanged => {
+                        info!(
+                            context,
+                            "Not scanning folder {} as its MODSEQ is unchanged since the last scan",
+                            foldername
+                        );
+                    }
+                    FolderChange::FlagsOrExpungeOnly => {
+                        info!(
+                            context,
+                            "Not scanning folder {} as CHANGEDSINCE shows only flag/expunge changes",
+                            foldername
+                        );
+                    }
+                    FolderChange::NewMail => {
+                        to_fetch.push(foldername.to_string());
+                    }
                 }
+            } else {
+                to_fetch.push(foldername.to_string());
             }
         }
 
@@ -84,10 +112,66 @@ impl Imap {
             .set_config(Config::ConfiguredSpamFolder, spam_folder.as_deref())
             .await?;
 
+        self.fetch_folders_concurrently(context, to_fetch).await;
+
+        let _ = dedupe_subaddressed_self_contacts(context)
+            .await
+            .ok_or_log_msg(context, "Can't dedupe subaddressed self-contacts");
+
         last_scan.replace(Instant::now());
         Ok(())
     }
 
+    /// Fetches `foldernames` using up to [`Config::MaxConcurrentImapConnections`]
+    /// concurrent IMAP sessions. A failure fetching one folder is logged and only
+    /// skips that folder, it never aborts the others.
+    async fn fetch_folders_concurrently(&mut self, context: &Context, foldernames: Vec<String>) {
+        let max_concurrent = context
+            .get_config_int(Config::MaxConcurrentImapConnections)
+            .await
+            .unwrap_or(3)
+            .max(1) as usize;
+
+        let mut foldernames = foldernames.into_iter();
+        let Some(first) = foldernames.next() else {
+            return;
+        };
+
+        if max_concurrent == 1 {
+            // Only one connection is allowed: fetch every folder sequentially on the
+            // session already held by `self` instead of opening another one.
+            for foldername in std::iter::once(first).chain(foldernames) {
+                info!(context, "Scanning folder: {}", foldername);
+                if let Err(e) = self.fetch_new_messages(context, &foldername, false).await {
+                    warn!(context, "Can't fetch new msgs in scanned folder: {:#}", e);
+                }
+            }
+            return;
+        }
+
+        // The session already held by `self` is reused for `first`; any further
+        // concurrency opens additional short-lived sessions. `first` is driven by
+        // `first_fut` below and `futures::join!`ed alongside the rest of the fan-out
+        // rather than `.await`ed up front, so it genuinely overlaps with the others
+        // instead of using up a "free" unit of concurrency before the window opens.
+        let first_fut = async {
+            info!(context, "Scanning folder: {}", first);
+            if let Err(e) = self.fetch_new_messages(context, &first, false).await {
+                warn!(context, "Can't fetch new msgs in scanned folder: {:#}", e);
+            }
+        };
+
+        // `first` already accounts for one unit of concurrency, so the remaining
+        // window here is `max_concurrent - 1`.
+        let rest_fut = run_bounded_concurrent(
+            foldernames.collect(),
+            max_concurrent.saturating_sub(1),
+            |foldername| fetch_one_folder(context, foldername),
+        );
+
+        futures::join!(first_fut, rest_fut);
+    }
+
     /// Returns the names of all folders on the IMAP server that are not in `exclude`.
     pub async fn list_folders_except(
         self: &mut Imap,
@@ -106,6 +190,131 @@ impl Imap {
     }
 }
 
+/// Runs `task` over `items` with at most `max_concurrent` instances in flight at once
+/// (clamped to at least 1, so a budget of 0 still makes progress instead of stalling),
+/// collecting nothing -- `task` must
+/// do its own error handling.
+async fn run_bounded_concurrent<T, F, Fut>(items: Vec<T>, max_concurrent: usize, mut task: F)
+where
+    F: FnMut(T) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let max_concurrent = max_concurrent.max(1);
+    let mut items = items.into_iter();
+    let mut pending = FuturesUnordered::new();
+    while pending.len() < max_concurrent {
+        let Some(item) = items.next() else {
+            break;
+        };
+        pending.push(task(item));
+    }
+    while let Some(()) = pending.next().await {
+        if let Some(item) = items.next() {
+            pending.push(task(item));
+        }
+    }
+}
+
+/// Opens its own short-lived IMAP session and fetches `foldername`, logging and
+/// swallowing any error so a single bad folder can't abort the rest of the fan-out.
+async fn fetch_one_folder(context: &Context, foldername: String) {
+    let mut imap = match Imap::connect(context).await {
+        Ok(imap) => imap,
+        Err(e) => {
+            warn!(
+                context,
+                "Can't open additional IMAP connection to scan folder {}: {:#}", foldername, e
+            );
+            return;
+        }
+    };
+    info!(context, "Scanning folder: {}", foldername);
+    if let Err(e) = imap.fetch_new_messages(context, &foldername, false).await {
+        warn!(
+            context,
+            "Can't fetch new msgs in scanned folder {}: {:#}", foldername, e
+        );
+    }
+}
+
+/// Removes any already-imported contact whose address is only a subaddress variant of the
+/// configured self-address (see [`Config::SubaddressingEnabled`]/[`Config::ConfiguredAddr`]),
+/// so a single identity that receives mail under several `local+tag@domain` aliases collapses
+/// to the real self-contact instead of accumulating one spurious contact per tag. Run once per
+/// full folder scan, after fetching, since that's when such contacts would have been created
+/// by message import.
+async fn dedupe_subaddressed_self_contacts(context: &Context) -> Result<()> {
+    if !context
+        .get_config_bool(Config::SubaddressingEnabled)
+        .await?
+    {
+        return Ok(());
+    }
+    let Some(self_addr) = context.get_config(Config::ConfiguredAddr).await? else {
+        return Ok(());
+    };
+    let Some((local, domain)) = self_addr.split_once('@') else {
+        return Ok(());
+    };
+    let separator = context
+        .get_config(Config::SubaddressingSeparator)
+        .await?
+        .unwrap_or_else(|| "+".to_string());
+    let pattern = subaddress_like_pattern(local, &separator, domain);
+
+    // The LIKE above is only a (properly escaped) pre-filter to avoid scanning every
+    // contact; `is_self_addr_with_subaddressing` is the single source of truth for
+    // whether a candidate really is a subaddress of `self_addr`, so this can't disagree
+    // with the check used elsewhere in the codebase.
+    let candidates: Vec<(i64, String)> = context
+        .sql
+        .query_map(
+            "SELECT id, addr FROM contacts WHERE addr LIKE ?1 ESCAPE '\\' AND addr <> ?2",
+            paramsv![pattern, self_addr],
+            |row| {
+                let id: i64 = row.get(0)?;
+                let addr: String = row.get(1)?;
+                Ok((id, addr))
+            },
+            |rows| {
+                rows.collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(Into::into)
+            },
+        )
+        .await?;
+
+    for (id, addr) in candidates {
+        if context.is_self_addr_with_subaddressing(&addr).await? {
+            context
+                .sql
+                .execute("DELETE FROM contacts WHERE id = ?1", paramsv![id])
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Escapes `%`/`_` so a `LIKE` pattern built from user-controlled address parts matches them
+/// as literal characters instead of SQL wildcards.
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Builds an (escaped) `LIKE ... ESCAPE '\'` pattern matching `local<separator><anything>@domain`.
+/// This is only ever used to pre-filter candidates cheaply -- the real
+/// subaddress check is [`Context::is_self_addr_with_subaddressing`] -- so an overly broad
+/// match here can at worst pull in one extra row to double-check, never cause a wrong delete.
+fn subaddress_like_pattern(local: &str, separator: &str, domain: &str) -> String {
+    format!(
+        "{}{}%@{}",
+        escape_like(local),
+        escape_like(separator),
+        escape_like(domain)
+    )
+}
+
 async fn get_watched_folders(context: &Context) -> Vec<String> {
     let mut res = Vec::new();
     let folder_watched_configured = &[
@@ -122,3 +331,92 @@ async fn get_watched_folders(context: &Context) -> Vec<String> {
     }
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_subaddress_like_pattern_escapes_wildcards() {
+        // Regression test: `_`/`%` in the local part or domain must be escaped, or they'd
+        // act as SQL LIKE wildcards and match addresses that aren't actually subaddresses.
+        assert_eq!(
+            subaddress_like_pattern("jo_h", "+", "example.com"),
+            "jo\\_h+%@example.com"
+        );
+        assert_eq!(
+            subaddress_like_pattern("jo%h", "+", "exa_ple.com"),
+            "jo\\%h+%@exa\\_ple.com"
+        );
+    }
+
+    /// Runs `items` through [`run_bounded_concurrent`] with a task that records how many
+    /// instances were ever in flight at once, and returns `(processed_items, observed_max)`.
+    async fn track_concurrency(items: Vec<u32>, max_concurrent: usize) -> (Vec<u32>, usize) {
+        let current = Arc::new(AtomicUsize::new(0));
+        let observed_max = Arc::new(AtomicUsize::new(0));
+        let processed = Arc::new(Mutex::new(Vec::new()));
+
+        run_bounded_concurrent(items, max_concurrent, |item| {
+            let current = current.clone();
+            let observed_max = observed_max.clone();
+            let processed = processed.clone();
+            async move {
+                let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                observed_max.fetch_max(now, Ordering::SeqCst);
+                async_std::task::sleep(Duration::from_millis(20)).await;
+                processed.lock().unwrap().push(item);
+                current.fetch_sub(1, Ordering::SeqCst);
+            }
+        })
+        .await;
+
+        let processed = Arc::try_unwrap(processed).unwrap().into_inner().unwrap();
+        (processed, observed_max.load(Ordering::SeqCst))
+    }
+
+    #[async_std::test]
+    async fn test_run_bounded_concurrent_processes_all_items_in_flight_at_once() {
+        let items: Vec<u32> = (0..5).collect();
+        let (mut processed, observed_max) = track_concurrency(items, 3).await;
+        processed.sort_unstable();
+        assert_eq!(processed, vec![0, 1, 2, 3, 4]);
+        // Regression test: the window must genuinely overlap, not serialize one item
+        // before the concurrent ones start.
+        assert!(observed_max > 1);
+        assert!(observed_max <= 3);
+    }
+
+    #[async_std::test]
+    async fn test_run_bounded_concurrent_max_one_still_processes_every_item() {
+        // Regression test for the `MaxConcurrentImapConnections = 1` edge case: a window of
+        // 1 must not drop any items even though the budget never exceeds 1 in flight.
+        let items: Vec<u32> = (0..3).collect();
+        let (mut processed, observed_max) = track_concurrency(items, 1).await;
+        processed.sort_unstable();
+        assert_eq!(processed, vec![0, 1, 2]);
+        assert_eq!(observed_max, 1);
+    }
+
+    #[async_std::test]
+    async fn test_run_bounded_concurrent_zero_budget_still_makes_progress() {
+        // A budget of 0 is clamped to 1 by `run_bounded_concurrent` -- it must still
+        // process every item instead of stalling.
+        let items: Vec<u32> = (0..3).collect();
+        let (mut processed, observed_max) = track_concurrency(items, 0).await;
+        processed.sort_unstable();
+        assert_eq!(processed, vec![0, 1, 2]);
+        assert_eq!(observed_max, 1);
+    }
+
+    #[async_std::test]
+    async fn test_run_bounded_concurrent_empty_items() {
+        let (processed, observed_max) = track_concurrency(Vec::new(), 3).await;
+        assert!(processed.is_empty());
+        assert_eq!(observed_max, 0);
+    }
+}
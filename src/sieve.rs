@@ -0,0 +1,403 @@
+//! # ManageSieve (RFC 5804) client.
+//!
+//! Lets the core upload and activate server-side Sieve scripts. The main use case is
+//! installing a filter that moves Chat-Version-tagged messages into the DeltaChat folder
+//! server-side, so IMAP's `scan_folders`/`MvboxMove` have less to do, and optionally a
+//! vacation/auto-reply rule.
+
+use anyhow::{bail, Context as _, Result};
+use async_native_tls::TlsStream;
+use async_std::io::{BufReader, ReadExt, WriteExt};
+use async_std::net::TcpStream;
+
+use crate::config::Config;
+use crate::context::Context;
+use crate::login_param::LoginParam;
+
+/// A single ManageSieve script as returned by `LISTSCRIPTS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SieveScript {
+    pub name: String,
+    pub active: bool,
+}
+
+/// Either side of the `STARTTLS` upgrade: plaintext until the client asks for
+/// encryption, then the remainder of the session runs over TLS.
+enum SieveStream {
+    Plain(BufReader<TcpStream>),
+    Tls(BufReader<TlsStream<TcpStream>>),
+}
+
+impl SieveStream {
+    async fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut buf = [0u8; 1];
+        let n = match self {
+            SieveStream::Plain(s) => s.read(&mut buf).await?,
+            SieveStream::Tls(s) => s.read(&mut buf).await?,
+        };
+        Ok((n != 0).then_some(buf[0]))
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            SieveStream::Plain(s) => s.write_all(data).await?,
+            SieveStream::Tls(s) => s.write_all(data).await?,
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        match self {
+            SieveStream::Plain(s) => s.flush().await?,
+            SieveStream::Tls(s) => s.flush().await?,
+        }
+        Ok(())
+    }
+}
+
+/// A connected ManageSieve session.
+pub struct SieveClient {
+    stream: SieveStream,
+}
+
+impl SieveClient {
+    /// Connects to `hostname:port` in plaintext, reads the server's greeting/capability
+    /// response, then upgrades to TLS via `STARTTLS` as RFC 5804 section 2.2 and most
+    /// real-world deployments (e.g. Dovecot's default port-4190 setup) require, re-reading
+    /// the capabilities the server re-announces after the TLS handshake.
+    pub async fn connect(hostname: &str, port: u16) -> Result<(Self, Vec<String>)> {
+        let tcp_stream = TcpStream::connect((hostname, port)).await?;
+        let mut client = Self {
+            stream: SieveStream::Plain(BufReader::new(tcp_stream)),
+        };
+        let capabilities = client.read_greeting().await?;
+        if !has_starttls(&capabilities) {
+            bail!("ManageSieve server at {} does not advertise STARTTLS", hostname);
+        }
+
+        client.send_command("STARTTLS").await?;
+        let tcp_stream = match client.stream {
+            SieveStream::Plain(buf_reader) => buf_reader.into_inner(),
+            SieveStream::Tls(_) => unreachable!("just connected in plaintext"),
+        };
+        let tls_stream = async_native_tls::connect(hostname, tcp_stream).await?;
+        client.stream = SieveStream::Tls(BufReader::new(tls_stream));
+
+        let capabilities = client.read_greeting().await?;
+        Ok((client, capabilities))
+    }
+
+    async fn read_greeting(&mut self) -> Result<Vec<String>> {
+        let mut capabilities = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if line.starts_with("OK") {
+                break;
+            }
+            capabilities.push(line.trim_start_matches('"').trim_end_matches('"').to_string());
+        }
+        Ok(capabilities)
+    }
+
+    /// Reads a single `\r\n`-terminated line, decoding it as UTF-8 rather than casting
+    /// bytes to `char` one at a time (which would corrupt any multi-byte UTF-8 the server
+    /// sends, e.g. in non-ASCII script names).
+    async fn read_line(&mut self) -> Result<String> {
+        let mut raw = Vec::new();
+        loop {
+            let byte = self
+                .stream
+                .read_byte()
+                .await?
+                .context("ManageSieve connection closed unexpectedly")?;
+            if byte == b'\n' {
+                break;
+            }
+            if byte != b'\r' {
+                raw.push(byte);
+            }
+        }
+        Ok(String::from_utf8_lossy(&raw).into_owned())
+    }
+
+    async fn send_command(&mut self, command: &str) -> Result<String> {
+        self.stream.write_all(command.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+
+        let mut response = String::new();
+        loop {
+            let line = self.read_line().await?;
+            let is_status_line = line.starts_with("OK")
+                || line.starts_with("NO")
+                || line.starts_with("BYE");
+            if is_status_line {
+                response.push_str(&line);
+                if line.starts_with("NO") {
+                    bail!("ManageSieve command failed: {}", line.trim());
+                }
+                break;
+            }
+            response.push_str(&line);
+            response.push('\n');
+        }
+        Ok(response)
+    }
+
+    /// Authenticates using `AUTHENTICATE "PLAIN"`.
+    pub async fn authenticate(&mut self, user: &str, pw: &str) -> Result<()> {
+        let auth = base64::encode(format!("\0{}\0{}", user, pw));
+        self.send_command(&format!("AUTHENTICATE \"PLAIN\" \"{}\"", auth))
+            .await?;
+        Ok(())
+    }
+
+    /// `PUTSCRIPT <name> <script>`, creating or overwriting the named script.
+    pub async fn put_script(&mut self, name: &str, script: &str) -> Result<()> {
+        self.send_command(&format!(
+            "PUTSCRIPT \"{}\" {{{}+}}\r\n{}",
+            name,
+            script.len(),
+            script
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// `SETACTIVE <name>`, activating the named script (or deactivating all scripts if
+    /// `name` is empty, per RFC 5804 section 2.8).
+    pub async fn set_active(&mut self, name: &str) -> Result<()> {
+        self.send_command(&format!("SETACTIVE \"{}\"", name)).await?;
+        Ok(())
+    }
+
+    /// `LISTSCRIPTS`, returning every script known to the server and which one is active.
+    pub async fn list_scripts(&mut self) -> Result<Vec<SieveScript>> {
+        let response = self.send_command("LISTSCRIPTS").await?;
+        Ok(parse_list_scripts_response(&response))
+    }
+}
+
+/// Parses the multi-line body of a `LISTSCRIPTS` response (one `"name" ["ACTIVE"]` per
+/// line) into [`SieveScript`]s. Split out of [`SieveClient::list_scripts`] so the parsing
+/// can be unit-tested without a live connection.
+fn parse_list_scripts_response(response: &str) -> Vec<SieveScript> {
+    let mut scripts = Vec::new();
+    for line in response.lines() {
+        if let Some(name) = line
+            .trim()
+            .strip_prefix('"')
+            .and_then(|s| s.split('"').next())
+        {
+            scripts.push(SieveScript {
+                name: name.to_string(),
+                active: line.trim_end().ends_with("ACTIVE"),
+            });
+        }
+    }
+    scripts
+}
+
+/// Whether the server's greeting/`CAPABILITY` response advertises `STARTTLS`.
+fn has_starttls(capabilities: &[String]) -> bool {
+    capabilities.iter().any(|c| c.eq_ignore_ascii_case("STARTTLS"))
+}
+
+/// The name under which Delta Chat installs its own filter, so later upgrades can find and
+/// replace it instead of accumulating duplicate scripts.
+const DELTACHAT_SCRIPT_NAME: &str = "deltachat-auto-mvbox";
+
+/// A Sieve rule moving Chat-Version-tagged messages into the DeltaChat folder.
+fn mvbox_sieve_script(mvbox_folder: &str) -> String {
+    format!(
+        "require [\"fileinto\"];\n\
+         if header :contains \"Chat-Version\" \"1.0\" {{\n\
+         \tfileinto \"{}\";\n\
+         }}\n",
+        mvbox_folder
+    )
+}
+
+/// The well-known ManageSieve port (RFC 5804 section 1.1), used when the user hasn't set
+/// `Config::SievePort` explicitly.
+const DEFAULT_SIEVE_PORT: u16 = 4190;
+
+impl Context {
+    /// Probes the ManageSieve server's capabilities without authenticating and returns them.
+    /// Nothing is stored.
+    pub async fn probe_sieve_capabilities(
+        &self,
+        hostname: &str,
+        port: u16,
+    ) -> Result<Vec<String>> {
+        let (_client, capabilities) = SieveClient::connect(hostname, port).await?;
+        Ok(capabilities)
+    }
+
+    /// Probes the configured (or, if unset, the mail server's) ManageSieve server and, on
+    /// success, writes `ConfiguredSieveServer`/`ConfiguredSievePort`/`ConfiguredSieveEnabled`
+    /// and activates the mvbox filter -- mirroring how `MailServer`/`SendServer` are probed
+    /// and turned into their `Configured*` counterparts during account configuration.
+    ///
+    /// Called from [`Context::set_config`] whenever `Config::SieveEnabled` is turned on.
+    pub(crate) async fn configure_sieve(&self) -> Result<()> {
+        let hostname = match self.get_config(Config::SieveServer).await? {
+            Some(hostname) => hostname,
+            None => self
+                .get_config(Config::ConfiguredMailServer)
+                .await?
+                .context("configure_sieve(): no sieve_server or configured mail_server")?,
+        };
+        let port: u16 = match self.get_config_int(Config::SievePort).await? {
+            0 => DEFAULT_SIEVE_PORT,
+            port => port
+                .try_into()
+                .context("configure_sieve(): invalid sieve_port")?,
+        };
+
+        self.probe_sieve_capabilities(&hostname, port)
+            .await
+            .context("configure_sieve(): ManageSieve server is not reachable")?;
+
+        self.set_config(Config::ConfiguredSieveServer, Some(&hostname))
+            .await?;
+        self.set_config(Config::ConfiguredSievePort, Some(&port.to_string()))
+            .await?;
+        self.set_config(Config::ConfiguredSieveEnabled, Some("1"))
+            .await?;
+
+        self.activate_sieve_mvbox_filter().await
+    }
+
+    async fn connect_sieve(&self) -> Result<SieveClient> {
+        let hostname = self
+            .get_config(Config::ConfiguredSieveServer)
+            .await?
+            .context("connect_sieve(): no ManageSieve server configured")?;
+        let port: u16 = self
+            .get_config_int(Config::ConfiguredSievePort)
+            .await?
+            .try_into()
+            .context("connect_sieve(): invalid ManageSieve port")?;
+        let lp = LoginParam::from_database(self, "configured_").await;
+        let (mut client, _capabilities) = SieveClient::connect(&hostname, port).await?;
+        client.authenticate(&lp.mail_user, &lp.mail_pw).await?;
+        Ok(client)
+    }
+
+    /// Uploads `script` under Delta Chat's own script name, creating or replacing it. The
+    /// script is not activated; see [`Context::activate_sieve_mvbox_filter`].
+    pub async fn put_sieve_script(&self, script: &str) -> Result<()> {
+        let mut client = self.connect_sieve().await?;
+        client.put_script(DELTACHAT_SCRIPT_NAME, script).await?;
+        Ok(())
+    }
+
+    /// Activates the Delta Chat mvbox-filtering script, generating it on the fly for the
+    /// folder that `ConfiguredMvboxFolder` currently points at.
+    pub async fn activate_sieve_mvbox_filter(&self) -> Result<()> {
+        let mvbox_folder = self
+            .get_config(Config::ConfiguredMvboxFolder)
+            .await?
+            .context("activate_sieve_mvbox_filter(): no mvbox folder configured")?;
+        let script = mvbox_sieve_script(&mvbox_folder);
+        self.put_sieve_script(&script).await?;
+        let mut client = self.connect_sieve().await?;
+        client.set_active(DELTACHAT_SCRIPT_NAME).await?;
+        Ok(())
+    }
+
+    /// Returns every script the server knows about for the configured account.
+    pub async fn list_sieve_scripts(&self) -> Result<Vec<SieveScript>> {
+        let mut client = self.connect_sieve().await?;
+        client.list_scripts().await
+    }
+
+    /// `SETACTIVE ""`, deactivating all Sieve scripts for the configured account.
+    pub async fn deactivate_sieve_scripts(&self) -> Result<()> {
+        let mut client = self.connect_sieve().await?;
+        client.set_active("").await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::TestContext;
+
+    #[async_std::test]
+    async fn test_set_config_sieve_enabled_wires_configure_sieve() {
+        let t = TestContext::new().await;
+        // No `SieveServer`/`ConfiguredMailServer` is configured, so turning `SieveEnabled`
+        // on must fail -- exercising that `set_config` actually calls `configure_sieve()`
+        // rather than just storing the flag.
+        let err = t
+            .set_config(Config::SieveEnabled, Some("1"))
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("sieve_server or configured mail_server"));
+        assert_eq!(
+            t.get_config(Config::ConfiguredSieveEnabled).await.unwrap(),
+            None
+        );
+    }
+
+    #[async_std::test]
+    async fn test_configure_sieve_ignores_stale_raw_mail_server() {
+        let t = TestContext::new().await;
+        // `MailServer` is the raw, unprobed key entered during account setup; it can be
+        // stale or empty by the time `SieveEnabled` is flipped, well after configuration.
+        // Only the already-probed `ConfiguredMailServer` may be used as a fallback, so
+        // this must still fail even though `MailServer` is set.
+        t.set_config(Config::MailServer, Some("stale.example.com"))
+            .await
+            .unwrap();
+        let err = t
+            .set_config(Config::SieveEnabled, Some("1"))
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("sieve_server or configured mail_server"));
+    }
+
+    #[test]
+    fn test_has_starttls() {
+        assert!(has_starttls(&["STARTTLS".to_string(), "SIEVE \"fileinto\"".to_string()]));
+        assert!(has_starttls(&["starttls".to_string()]));
+        assert!(!has_starttls(&["SIEVE \"fileinto\"".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_list_scripts_response() {
+        let response = "\"summer\"\n\"main\" ACTIVE\n\"NOTIFY-test\"\n";
+        let scripts = parse_list_scripts_response(response);
+        assert_eq!(
+            scripts,
+            vec![
+                SieveScript {
+                    name: "summer".to_string(),
+                    active: false,
+                },
+                SieveScript {
+                    name: "main".to_string(),
+                    active: true,
+                },
+                SieveScript {
+                    name: "NOTIFY-test".to_string(),
+                    active: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mvbox_sieve_script_contains_folder() {
+        let script = mvbox_sieve_script("DeltaChat");
+        assert!(script.contains("fileinto \"DeltaChat\";"));
+    }
+}